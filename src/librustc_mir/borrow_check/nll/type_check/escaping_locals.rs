@@ -8,14 +8,14 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-//! A MIR walk gathering a union-find of assigned locals, for the purpose of locating the ones
-//! escaping into the output.
+//! A MIR walk gathering a directed "flows-into" graph of assigned locals, for the purpose of
+//! locating the ones escaping into the output.
 
 use rustc::mir::visit::Visitor;
 use rustc::mir::*;
 
 use rustc_data_structures::indexed_vec::Idx;
-use rustc_data_structures::unify as ut;
+use rustc_data_structures::fx::{FxHashMap, FxHashSet};
 
 crate trait FindEscapingLocals {
     /// Finds all locals ultimately flowing into the output
@@ -25,112 +25,185 @@ crate trait FindEscapingLocals {
 
 impl<'tcx> FindEscapingLocals for Mir<'tcx> {
     fn find_escaping_locals(&self) -> Vec<Local> {
-        let mut escaping_locals = Vec::new();
-
         let mut visitor = GatherAssignedLocalsVisitor::new();
         visitor.visit_mir(self);
+        escaping_locals_from_graph(&visitor)
+    }
+}
 
-        // Check which local ultimately flowed into the output
-        let return_place = AssignedLocal(0);
-        for (local, _) in self.local_decls.iter_enumerated() {
-            if local.index() == 0 {
-                continue;
-            }
+// The return place is always `Local` 0. Walk the "flows-into" graph
+// backwards from it: a local escapes if some path rooted at it
+// (transitively) flows into the return place. Pulled out of
+// `find_escaping_locals` so the graph walk can be exercised directly in
+// tests, without needing a real `Mir` to run the visitor over.
+fn escaping_locals_from_graph(visitor: &GatherAssignedLocalsVisitor) -> Vec<Local> {
+    let return_path = TrackedPath {
+        local: Local::new(0),
+        projection: vec![],
+    };
 
-            let assigned_local = AssignedLocal::from(local);
-            if visitor
-                .unification_table
-                .unioned(return_place, assigned_local)
-            {
-                escaping_locals.push(local);
-            }
+    let mut escaping_locals = FxHashSet::default();
+    let mut seen = FxHashSet::default();
+    let mut worklist = vec![return_path];
+
+    while let Some(path) = worklist.pop() {
+        if !seen.insert(path.clone()) {
+            continue;
         }
 
-        escaping_locals
+        if path.local.index() != 0 {
+            escaping_locals.insert(path.local);
+        }
+
+        if let Some(preds) = visitor.flows_into.get(&path) {
+            worklist.extend(preds.iter().cloned());
+        }
+
+        // Reading `path` conservatively also reads every more specific
+        // field path recorded under the same local: e.g. field-sensitive
+        // edges are only ever recorded *into* `x.0`/`x.1`, so reading `x`
+        // as a whole (a plain `Use`/`Move` of the local, with no
+        // projection) must still pull in whatever flowed into each of
+        // its tracked fields, or the fields silently fail to show up as
+        // escaping once the aggregate they belong to is moved/returned
+        // as a unit.
+        if let Some(candidates) = visitor.paths_by_local.get(&path.local) {
+            for candidate in candidates {
+                if *candidate != path && candidate.projection.starts_with(&path.projection) {
+                    if let Some(preds) = visitor.flows_into.get(candidate) {
+                        worklist.extend(preds.iter().cloned());
+                    }
+                }
+            }
+        }
     }
-}
 
-/// The MIR visitor gathering the union-find of the locals used in
-/// assignments.
-struct GatherAssignedLocalsVisitor {
-    unification_table: ut::UnificationTable<ut::InPlace<AssignedLocal>>,
+    escaping_locals.into_iter().collect()
 }
 
-#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
-struct AssignedLocal(u32);
-
-impl ut::UnifyKey for AssignedLocal {
-    type Value = ();
+/// A path into a local: the local itself, plus the chain of field/variant
+/// projections reaching into it. Anything we can't represent precisely (a
+/// `Deref`, `Index`, `ConstantIndex` or `Subslice`) widens to the local as a
+/// whole, which is conservative: it just means we are more willing to link
+/// paths that aren't actually related.
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+struct TrackedPath {
+    local: Local,
+    projection: Vec<PathElem>,
+}
 
-    fn index(&self) -> u32 {
-        self.0
+impl TrackedPath {
+    fn field(&self, field: Field) -> TrackedPath {
+        let mut projection = self.projection.clone();
+        projection.push(PathElem::Field(field));
+        TrackedPath {
+            local: self.local,
+            projection,
+        }
     }
 
-    fn from_index(i: u32) -> AssignedLocal {
-        AssignedLocal(i)
+    fn downcast(&self, variant: usize) -> TrackedPath {
+        let mut projection = self.projection.clone();
+        projection.push(PathElem::Downcast(variant));
+        TrackedPath {
+            local: self.local,
+            projection,
+        }
     }
+}
 
-    fn tag() -> &'static str {
-        "AssignedLocal"
-    }
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+enum PathElem {
+    Field(Field),
+    Downcast(usize),
 }
 
-impl From<Local> for AssignedLocal {
-    fn from(item: Local) -> Self {
-        // newtype_indexes use usize but are u32s.
-        assert!(item.index() < ::std::u32::MAX as usize);
-        AssignedLocal(item.index() as u32)
-    }
+/// The MIR visitor gathering the directed graph of paths flowing into other
+/// paths through assignments. `flows_into[d]` contains every path `s` such
+/// that the visited MIR body contains (or conservatively might contain) a
+/// data flow from `s` into `d`, e.g. via an assignment `d = s` or `d = &s`.
+struct GatherAssignedLocalsVisitor {
+    flows_into: FxHashMap<TrackedPath, Vec<TrackedPath>>,
+    /// Every path we've ever recorded as a destination, indexed by its base
+    /// `Local`, so the backward walk can find the field paths tracked for a
+    /// local without scanning the whole `flows_into` map.
+    paths_by_local: FxHashMap<Local, Vec<TrackedPath>>,
 }
 
 impl GatherAssignedLocalsVisitor {
     fn new() -> Self {
         Self {
-            unification_table: ut::UnificationTable::new(),
+            flows_into: FxHashMap::default(),
+            paths_by_local: FxHashMap::default(),
         }
     }
 
-    fn union_locals_if_needed(&mut self, lvalue: Option<Local>, rvalue: Option<Local>) {
-        if let Some(lvalue) = lvalue {
-            if let Some(rvalue) = rvalue {
-                if lvalue != rvalue {
-                    self.unification_table
-                        .union(AssignedLocal::from(lvalue), AssignedLocal::from(rvalue));
+    fn flow_into_if_needed(
+        &mut self,
+        destination: Option<TrackedPath>,
+        source: Option<TrackedPath>,
+    ) {
+        if let Some(destination) = destination {
+            if let Some(source) = source {
+                if destination != source {
+                    self.paths_by_local
+                        .entry(destination.local)
+                        .or_insert_with(Vec::new)
+                        .push(destination.clone());
+                    self.flows_into
+                        .entry(destination)
+                        .or_insert_with(Vec::new)
+                        .push(source);
                 }
             }
         }
     }
 }
 
-// Returns the potential `Local` associated to this `Place` or `PlaceProjection`
-fn find_local_in_place(place: &Place) -> Option<Local> {
+// Returns the potential `TrackedPath` associated to this `Place` or `PlaceProjection`.
+// `Field`/`Downcast` projections are tracked precisely; everything else (in
+// particular `Deref`) conservatively widens to the underlying local.
+fn find_local_in_place(place: &Place) -> Option<TrackedPath> {
     match place {
-        Place::Local(local) => Some(*local),
-        Place::Projection(proj) => find_local_in_place(&proj.base),
+        Place::Local(local) => Some(TrackedPath {
+            local: *local,
+            projection: vec![],
+        }),
+
+        Place::Projection(proj) => {
+            let base = find_local_in_place(&proj.base)?;
+            match proj.elem {
+                ProjectionElem::Field(field, _) => Some(base.field(field)),
+                ProjectionElem::Downcast(_, variant) => {
+                    let mut projection = base.projection;
+                    projection.push(PathElem::Downcast(variant));
+                    Some(TrackedPath {
+                        local: base.local,
+                        projection,
+                    })
+                }
+                _ => Some(TrackedPath {
+                    local: base.local,
+                    projection: vec![],
+                }),
+            }
+        }
+
         _ => None,
     }
 }
 
-// Returns the potential `Local` in this `Operand`.
-fn find_local_in_operand(op: &Operand) -> Option<Local> {
-    // Conservatively check a subset of `Operand`s we know our
-    // benchmarks track, for example `html5ever`.
+// Returns the potential `TrackedPath` in this `Operand`.
+fn find_local_in_operand(op: &Operand) -> Option<TrackedPath> {
+    // `Copy` operands can still carry a value (e.g. a raw pointer) out to
+    // the destination, so they are tracked exactly like `Move`.
     match op {
-        Operand::Move(ref place) => find_local_in_place(place),
-        _ => None,
+        Operand::Move(ref place) | Operand::Copy(ref place) => find_local_in_place(place),
+        Operand::Constant(_) => None,
     }
 }
 
 impl<'tcx> Visitor<'tcx> for GatherAssignedLocalsVisitor {
-    fn visit_mir(&mut self, mir: &Mir<'tcx>) {
-        // We need as many union-find keys as there are locals
-        for _ in 0..mir.local_decls.len() {
-            self.unification_table.new_key(());
-        }
-
-        self.super_mir(mir);
-    }
-
     fn visit_assign(
         &mut self,
         block: BasicBlock,
@@ -143,19 +216,43 @@ impl<'tcx> Visitor<'tcx> for GatherAssignedLocalsVisitor {
         // Conservatively check a subset of `Rvalue`s we know our
         // benchmarks track, for example `html5ever`.
         match rvalue {
-            Rvalue::Use(op) => self.union_locals_if_needed(local, find_local_in_operand(op)),
+            Rvalue::Use(op) => self.flow_into_if_needed(local, find_local_in_operand(op)),
             Rvalue::Ref(_, _, place) => {
-                self.union_locals_if_needed(local, find_local_in_place(place))
+                self.flow_into_if_needed(local, find_local_in_place(place))
             }
 
-            Rvalue::Cast(kind, op, _) => match kind {
-                CastKind::Unsize => self.union_locals_if_needed(local, find_local_in_operand(op)),
-                _ => (),
-            },
+            // Any cast (not just an unsizing one) can carry pointer
+            // provenance through to the destination, e.g. a pointer-to-
+            // pointer or pointer-to-integer cast.
+            Rvalue::Cast(_, op, _) => self.flow_into_if_needed(local, find_local_in_operand(op)),
+
+            // Binary and unary operations propagate the provenance of any
+            // pointer-carrying operand to the destination, e.g. pointer
+            // arithmetic via `Offset` or comparisons that are later
+            // reconstructed into a pointer.
+            Rvalue::BinaryOp(_, lhs, rhs) | Rvalue::CheckedBinaryOp(_, lhs, rhs) => {
+                self.flow_into_if_needed(local.clone(), find_local_in_operand(lhs));
+                self.flow_into_if_needed(local, find_local_in_operand(rhs));
+            }
 
-            Rvalue::Aggregate(_, ops) => {
-                for rvalue in ops.iter().map(find_local_in_operand) {
-                    self.union_locals_if_needed(local, rvalue);
+            Rvalue::UnaryOp(_, op) => self.flow_into_if_needed(local, find_local_in_operand(op)),
+
+            Rvalue::Aggregate(kind, ops) => {
+                // Field-sensitive: operand `i` flows into field `i` of the
+                // destination, not into the destination as a whole. Reading
+                // a field back out of an enum variant always goes through a
+                // `Downcast` projection first (see `find_local_in_place`),
+                // so push that same `Downcast` here, or the write-side and
+                // read-side keys for the field would never agree.
+                if let Some(ref local) = local {
+                    let local = match **kind {
+                        AggregateKind::Adt(_, variant, ..) => local.downcast(variant),
+                        _ => local.clone(),
+                    };
+                    for (index, op) in ops.iter().enumerate() {
+                        let field = local.field(Field::new(index));
+                        self.flow_into_if_needed(Some(field), find_local_in_operand(op));
+                    }
                 }
             }
 
@@ -164,4 +261,203 @@ impl<'tcx> Visitor<'tcx> for GatherAssignedLocalsVisitor {
 
         self.super_assign(block, place, rvalue, location);
     }
+
+    fn visit_terminator_kind(
+        &mut self,
+        block: BasicBlock,
+        kind: &TerminatorKind<'tcx>,
+        location: Location,
+    ) {
+        match kind {
+            TerminatorKind::Call {
+                args, destination, ..
+            } => {
+                if let Some((destination, _)) = destination {
+                    let destination = find_local_in_place(destination);
+
+                    // An argument may simply be returned by the callee, so treat
+                    // it as flowing into the call's destination. We don't also
+                    // flow the destination back out through the arguments: the
+                    // only way that edge would ever fire is if some other
+                    // (unrelated) route already marked an argument as escaping,
+                    // at which point this analysis already has what it needs.
+                    for arg in args {
+                        self.flow_into_if_needed(destination.clone(), find_local_in_operand(arg));
+                    }
+                }
+            }
+
+            // `DropAndReplace` performs `location = value` as part of
+            // running the drop, so it's a real assignment and needs the
+            // same treatment as one.
+            TerminatorKind::DropAndReplace { location: place, value, .. } => {
+                self.flow_into_if_needed(find_local_in_place(place), find_local_in_operand(value));
+            }
+
+            _ => (),
+        }
+
+        self.super_terminator_kind(block, kind, location);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // `fn pair(a: u32, b: u32) -> (u32, u32) { let tmp = (a, b); tmp }`:
+    // `a`/`b` only flow into the fields of `tmp`, and `tmp` as a whole only
+    // flows into the return place, so the backward walk has to expand a
+    // plain path into its tracked field paths to find them.
+    #[test]
+    fn aggregate_whole_move_reports_fields_as_escaping() {
+        let ret = Local::new(0);
+        let tmp = Local::new(1);
+        let a = Local::new(2);
+        let b = Local::new(3);
+
+        let mut visitor = GatherAssignedLocalsVisitor::new();
+        let tmp_path = TrackedPath {
+            local: tmp,
+            projection: vec![],
+        };
+        visitor.flow_into_if_needed(
+            Some(tmp_path.field(Field::new(0))),
+            Some(TrackedPath {
+                local: a,
+                projection: vec![],
+            }),
+        );
+        visitor.flow_into_if_needed(
+            Some(tmp_path.field(Field::new(1))),
+            Some(TrackedPath {
+                local: b,
+                projection: vec![],
+            }),
+        );
+        visitor.flow_into_if_needed(
+            Some(TrackedPath {
+                local: ret,
+                projection: vec![],
+            }),
+            Some(tmp_path),
+        );
+
+        let mut escaping = escaping_locals_from_graph(&visitor);
+        escaping.sort();
+        assert_eq!(escaping, vec![tmp, a, b]);
+    }
+
+    // `fn f(x: i32) -> i32 { match Some(x) { Some(y) => y, None => 0 } }`:
+    // the `Aggregate` that builds `Some(x)` has to record the field write
+    // under the same `Downcast`-qualified path that reading `y` back out of
+    // the matched place uses, or the two never meet in the graph.
+    #[test]
+    fn enum_field_write_and_read_paths_agree() {
+        let ret = Local::new(0);
+        let opt = Local::new(1);
+        let x = Local::new(2);
+        let y = Local::new(3);
+        let some_variant = 0;
+
+        let mut visitor = GatherAssignedLocalsVisitor::new();
+        let opt_path = TrackedPath {
+            local: opt,
+            projection: vec![],
+        };
+
+        // Write side: `opt = Some(x)`, as the `Aggregate` arm of
+        // `visit_assign` records it.
+        visitor.flow_into_if_needed(
+            Some(opt_path.downcast(some_variant).field(Field::new(0))),
+            Some(TrackedPath {
+                local: x,
+                projection: vec![],
+            }),
+        );
+
+        // Read side: `y = move (opt as Some).0`, as `find_local_in_place`
+        // would resolve the `Downcast` then `Field` projections.
+        visitor.flow_into_if_needed(
+            Some(TrackedPath {
+                local: y,
+                projection: vec![],
+            }),
+            Some(opt_path.downcast(some_variant).field(Field::new(0))),
+        );
+        visitor.flow_into_if_needed(
+            Some(TrackedPath {
+                local: ret,
+                projection: vec![],
+            }),
+            Some(TrackedPath {
+                local: y,
+                projection: vec![],
+            }),
+        );
+
+        let mut escaping = escaping_locals_from_graph(&visitor);
+        escaping.sort();
+        assert_eq!(escaping, vec![opt, x, y]);
+    }
+
+    // `find_local_in_operand` must track `Copy` exactly like `Move`: a value
+    // that only ever reaches the return place through a `Copy` operand (e.g.
+    // `fn pick(cond: bool, a: u32, b: u32) -> u32 { if cond { a } else { b } }`,
+    // where both arms merely copy their argument) still has to be reported.
+    #[test]
+    fn copy_operand_resolves_same_path_as_move() {
+        let a = TrackedPath {
+            local: Local::new(2),
+            projection: vec![],
+        };
+        let place = Place::Local(a.local);
+
+        assert_eq!(
+            find_local_in_operand(&Operand::Copy(place.clone())),
+            Some(a.clone())
+        );
+        assert_eq!(find_local_in_operand(&Operand::Move(place)), Some(a));
+    }
+
+    // A pointer reaching the return place only through pointer arithmetic
+    // (`p.offset(len)`, a `BinaryOp`) must still be tracked: both operands of
+    // a `BinaryOp`/`CheckedBinaryOp` flow into the destination, exactly as
+    // `visit_assign` records it, so the walk finds `p` as escaping even
+    // though it's never the "whole" value assigned.
+    #[test]
+    fn binary_op_propagates_both_operands() {
+        let ret = Local::new(0);
+        let dest = Local::new(1);
+        let p = Local::new(2);
+        let len = Local::new(3);
+
+        let mut visitor = GatherAssignedLocalsVisitor::new();
+        let dest_path = TrackedPath {
+            local: dest,
+            projection: vec![],
+        };
+
+        // `dest = Offset(move p, copy len)`: each operand's `TrackedPath`
+        // flows into `dest`.
+        visitor.flow_into_if_needed(
+            Some(dest_path.clone()),
+            find_local_in_operand(&Operand::Move(Place::Local(p))),
+        );
+        visitor.flow_into_if_needed(
+            Some(dest_path.clone()),
+            find_local_in_operand(&Operand::Copy(Place::Local(len))),
+        );
+        visitor.flow_into_if_needed(
+            Some(TrackedPath {
+                local: ret,
+                projection: vec![],
+            }),
+            Some(dest_path),
+        );
+
+        let mut escaping = escaping_locals_from_graph(&visitor);
+        escaping.sort();
+        assert_eq!(escaping, vec![dest, p, len]);
+    }
 }